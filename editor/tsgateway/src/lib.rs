@@ -1,41 +1,48 @@
+// This crate is a C-callable FFI gateway: every `pub extern "C" fn` takes
+// raw pointers handed in by the host by design, so clippy's
+// "mark it `unsafe`" suggestion would just push the `unsafe` onto every
+// call site in this file's own tests without making the FFI boundary any
+// safer.
+#![allow(clippy::not_unsafe_ptr_arg_deref)]
+
 use std::ffi::{CStr, CString};
 use std::os::raw::{c_char, c_int};
 use std::{
     fs,
     io::Write,
 };
-use tree_sitter::{Language, Parser, Tree};
+use tree_sitter::{InputEdit, Language, Parser, Point, Tree};
 use streaming_iterator::StreamingIterator;
 
-use tree_sitter_c;
-use tree_sitter_python;
-use tree_sitter_rust;
-
 const RUST_HIGHLIGHT_QUERY: &str = r#"
 ; Functions
 ((function_item name: (identifier) @function))
-((function_signature name: (identifier) @function))
+((function_signature_item name: (identifier) @function))
 ((call_expression function: (identifier) @function.call))
 
 ; Types and Structs
 (type_identifier) @type
 (struct_item name: (type_identifier) @type)
 (enum_item name: (type_identifier) @type)
-((trait_item name: (identifier) @trait))
-((implementation_item type: (type_identifier) @type))
+((trait_item name: (type_identifier) @trait))
+((impl_item type: (type_identifier) @type))
 
 ; Keywords and Modifiers
 [
- "fn" "let" "mut" "struct" "enum" "impl" "use" "as" "pub"
- "crate" "return" "if" "else" "match" "while" "for" "loop"
+ "fn" "let" "struct" "enum" "impl" "use" "as" "pub"
+ "return" "if" "else" "match" "while" "for" "loop"
  "in" "true" "false" "const" "static"
 ] @keyword
+(mutable_specifier) @keyword
+(crate) @keyword
 
 ; Literals and Comments
 (string_literal) @string
-(character_literal) @string
-(number_literal) @number
-(comment) @comment
+(char_literal) @string
+(integer_literal) @number
+(float_literal) @number
+(line_comment) @comment
+(block_comment) @comment
 
 ; Variables
 (identifier) @variable
@@ -116,22 +123,34 @@ const C_HIGHLIGHT_QUERY: &str = r#"
 (identifier) @variable
 "#;
 
-// const MARKDOWN_HIGHLIGHT_QUERY: &str = r#"
-// (heading) @heading
-// (emphasis) @emphasis
-// (strong_emphasis) @strong
-// (code_span) @inline_code
-// (fenced_code_block) @code_block
-// (link_text) @link_text
-// (link_destination) @link_destination
-// (image_description) @image_alt
-// (image_destination) @link_destination
-// (list_item) @list_item
-// (block_quote) @blockquote
-// (thematic_break) @separator
-// "```" @punctuation
-// "`" @punctuation
-// "#;
+// tree-sitter-markdown is a split grammar: `tree_sitter_md::LANGUAGE` only
+// parses block structure (headings, lists, code fences, ...) and leaves
+// inline spans (emphasis, links, code spans, ...) as opaque text nodes
+// for a second pass with `INLINE_LANGUAGE`, which this gateway doesn't
+// run. `Query::new` fails outright if it references a node kind the
+// grammar doesn't have, so this query is restricted to node kinds that
+// genuinely exist in the block grammar.
+const MARKDOWN_HIGHLIGHT_QUERY: &str = r#"
+(atx_heading) @heading
+(list_marker_minus) @list_item
+(list_marker_plus) @list_item
+(list_marker_star) @list_item
+(block_quote_marker) @blockquote
+(thematic_break) @separator
+"#;
+
+/// Marks fenced code blocks for injection: `@injection.content` is the
+/// byte range to re-parse with the nested grammar, `@injection.language`
+/// is the capture whose text names it (the fence's info string, e.g.
+/// "rust" in a \`\`\`rust block). `language` is a genuine (optional) child
+/// node of `info_string` in the block grammar, so it's captured directly
+/// rather than the whole `info_string` node.
+const MARKDOWN_INJECTION_QUERY: &str = r#"
+(fenced_code_block
+  (info_string
+    (language) @injection.language)
+  (code_fence_content) @injection.content)
+"#;
 
 // const JSON_HIGHLIGHT_QUERY: &str = r#"
 // ; Keys and values
@@ -157,12 +176,48 @@ pub struct TSResult {
     root_sexpr: *mut c_char,
 }
 
+/// `start`/`end` are byte offsets into the source; `start_row`/`start_col`
+/// and `end_row`/`end_col` are the same span as tree-sitter `Point`s (row
+/// plus UTF-8 byte column), so a renderer can place highlights without a
+/// second pass over the source to build a line index.
 #[repr(C)]
 pub struct Token {
     pub start: u32,
     pub end: u32,
     pub kind_id: u16,
     pub _pad: u16,
+    pub start_row: u32,
+    pub start_col: u32,
+    pub end_row: u32,
+    pub end_col: u32,
+}
+
+#[repr(C)]
+pub struct ByteRange {
+    pub start: u32,
+    pub end: u32,
+}
+
+/// Discriminates the two ways tree-sitter marks broken input: `Error`
+/// nodes wrap text the grammar couldn't make sense of, `Missing` nodes
+/// mark a token the parser inserted to recover (nothing is consumed).
+#[repr(C)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum DiagnosticKind {
+    Error = 0,
+    Missing = 1,
+}
+
+#[repr(C)]
+pub struct Diagnostic {
+    pub start: u32,
+    pub end: u32,
+    pub start_row: u32,
+    pub start_col: u32,
+    pub end_row: u32,
+    pub end_col: u32,
+    pub kind: DiagnosticKind,
+    pub node_kind: *mut c_char,
 }
 
 #[unsafe(no_mangle)]
@@ -216,12 +271,18 @@ pub extern "C" fn ts_get_tokens(
             if end < start {
                 std::mem::swap(&mut start, &mut end);
             }
+            let start_pos = node.start_position();
+            let end_pos = node.end_position();
 
             tokens.push(Token {
                 start,
                 end,
-                kind_id: hash_kind(node.kind()) as u16,
+                kind_id: syntax_kind_id(node.kind()),
                 _pad: 0,
+                start_row: start_pos.row as u32,
+                start_col: start_pos.column as u32,
+                end_row: end_pos.row as u32,
+                end_col: end_pos.column as u32,
             });
         } else {
             for i in 0..node.child_count() {
@@ -242,89 +303,448 @@ pub extern "C" fn ts_get_tokens(
     len
 }
 
+/// Like `ts_get_tokens`, but fills every gap between leaves with a
+/// synthetic `whitespace`-kind token so the returned stream is gap-free
+/// and strictly covers `0..src_len`. Each token ends up owning the
+/// trivia that precedes it, which lets a host rebuild the exact source
+/// from the token stream alone, without holding the original buffer.
 #[unsafe(no_mangle)]
-pub extern "C" fn ts_get_highlight_tokens(
+pub extern "C" fn ts_get_tokens_with_trivia(
     source: *const c_char,
     lang_id: c_int,
     out_tokens: *mut *mut Token,
 ) -> usize {
-    use tree_sitter::{Parser};
     if source.is_null() || out_tokens.is_null() {
         return 0;
     }
-    
-    let c_src = unsafe{ CStr::from_ptr(source) };
+    let c_src = unsafe { CStr::from_ptr(source) };
     let Ok(code) = c_src.to_str() else { return 0 };
-    
+
     let mut parser = Parser::new();
-    let lang = match get_language(lang_id) {
-        Some(l) => l,
-        None => return 0,
-    };
-    parser.set_language(&lang).ok();
-    
-    let Some(tree) = parser.parse(code, None) else { return 0 };
-    
-    let query_source = match lang_id {
-        0 => RUST_HIGHLIGHT_QUERY, 
-        1 => C_HIGHLIGHT_QUERY,
-        2 => PYTHON_HIGHLIGHT_QUERY,
-        3 => ODIN_HIGHLIGHT_QUERY,
-        _ => return 0,
+    let Some(lang) = get_language(lang_id) else {
+        return 0;
     };
-    
-    let query = match tree_sitter::Query::new(&lang.into(), query_source) {
-        Ok(q) => q,
-        Err(_) => return 0,
+    if parser.set_language(&lang).is_err() {
+        return 0;
+    }
+    let Some(tree) = parser.parse(code, None) else {
+        return 0;
     };
-    
-    let mut cursor = tree_sitter::QueryCursor::new();
     let root = tree.root_node();
-    let _bytes = code.as_bytes();
-    let mut matches = cursor.matches(&query, root, _bytes); 
-    let mut tokens: Vec<Token> = Vec::with_capacity(256);
+    let src_len = code.len() as u32;
 
-    loop {
-        matches.advance();
-        let m = matches.get();
-        if m.is_none() {
-            break;
+    // Walk the tree and collect leaf nodes.
+    let mut leaves: Vec<Token> = Vec::with_capacity(256);
+    let mut stack = Vec::with_capacity(256);
+    stack.push(root);
+
+    while let Some(node) = stack.pop() {
+        if node.child_count() == 0 {
+            let mut start = node.start_byte() as u32;
+            let mut end = node.end_byte() as u32;
+            if start > src_len {
+                start = src_len;
+            }
+            if end > src_len {
+                end = src_len;
+            }
+            if end < start {
+                std::mem::swap(&mut start, &mut end);
+            }
+            let start_pos = node.start_position();
+            let end_pos = node.end_position();
+
+            leaves.push(Token {
+                start,
+                end,
+                kind_id: syntax_kind_id(node.kind()),
+                _pad: 0,
+                start_row: start_pos.row as u32,
+                start_col: start_pos.column as u32,
+                end_row: end_pos.row as u32,
+                end_col: end_pos.column as u32,
+            });
+        } else {
+            for i in 0..node.child_count() {
+                if let Some(child) = node.child(i) {
+                    stack.push(child);
+                }
+            }
         }
-        let m = m.unwrap();
-        
-        for cap in m.captures {
-            let node = cap.node;
-            let name = query.capture_names()[cap.index as usize];
-            tokens.push(Token{
-                start: node.start_byte() as u32,
-                end: node.end_byte() as u32,
-                kind_id: hash_kind(name) as u16,
+    }
+
+    // The traversal above pushes children in forward order onto a stack
+    // and pops, so leaves come out unsorted; sort by start byte before
+    // scanning pairwise to fill the gaps between them. Byte offsets are
+    // used throughout, never char indices, so multi-byte UTF-8
+    // boundaries are respected.
+    leaves.sort_by_key(|t| t.start);
+
+    let trivia_kind = syntax_kind_id("whitespace");
+    let root_end_pos = root.end_position();
+    let mut tokens: Vec<Token> = Vec::with_capacity(leaves.len() * 2 + 1);
+    let mut prev_end = 0u32;
+    let mut prev_end_row = 0u32;
+    let mut prev_end_col = 0u32;
+
+    for leaf in leaves {
+        if leaf.start > prev_end {
+            tokens.push(Token {
+                start: prev_end,
+                end: leaf.start,
+                kind_id: trivia_kind,
                 _pad: 0,
+                start_row: prev_end_row,
+                start_col: prev_end_col,
+                end_row: leaf.start_row,
+                end_col: leaf.start_col,
             });
         }
+        prev_end = leaf.end;
+        prev_end_row = leaf.end_row;
+        prev_end_col = leaf.end_col;
+        tokens.push(leaf);
     }
-    
+
+    if prev_end < src_len {
+        tokens.push(Token {
+            start: prev_end,
+            end: src_len,
+            kind_id: trivia_kind,
+            _pad: 0,
+            start_row: prev_end_row,
+            start_col: prev_end_col,
+            end_row: root_end_pos.row as u32,
+            end_col: root_end_pos.column as u32,
+        });
+    }
+
+    let len = tokens.len();
+    let boxed = tokens.into_boxed_slice();
+    let ptr = boxed.as_ptr() as *mut Token;
+    std::mem::forget(boxed);
+    unsafe {
+        *out_tokens = ptr;
+    }
+    len
+}
+
+/// Highlights `source` with `lang_id`'s grammar. When the grammar defines
+/// an injection query (currently just Markdown's fenced code blocks),
+/// each injected region is parsed with its own nested grammar and its
+/// tokens are spliced into the result with document-absolute offsets, so
+/// e.g. a Markdown file comes back with its Rust/Python code blocks
+/// highlighted by their own grammars in the same token stream.
+#[unsafe(no_mangle)]
+pub extern "C" fn ts_get_highlight_tokens(
+    source: *const c_char,
+    lang_id: c_int,
+    out_tokens: *mut *mut Token,
+) -> usize {
+    if source.is_null() || out_tokens.is_null() {
+        return 0;
+    }
+
+    let c_src = unsafe { CStr::from_ptr(source) };
+    let Ok(code) = c_src.to_str() else { return 0 };
+
+    if get_language(lang_id).is_none() {
+        return 0;
+    }
+
+    let tokens = collect_highlight_tokens(code, lang_id, 0, 0, 0, 0);
+
     let len = tokens.len();
     let boxed = tokens.into_boxed_slice();
     let ptr = boxed.as_ptr() as *mut Token;
     std::mem::forget(boxed);
-    unsafe { *out_tokens = ptr; }
+    unsafe {
+        *out_tokens = ptr;
+    }
     len
 }
 
 #[unsafe(no_mangle)]
 pub extern "C" fn ts_free_tokens(ptr: *mut Token, len: usize) {
     if !ptr.is_null() {
-        unsafe { drop(Box::from_raw(std::slice::from_raw_parts_mut(ptr, len))) }
+        unsafe { drop(Box::from_raw(std::ptr::slice_from_raw_parts_mut(ptr, len))) }
     }
 }
 
-fn hash_kind(kind: &str) -> u16 {
-    let mut h: u32 = 2166136261;
-    for &b in kind.as_bytes() {
-        h = (h ^ b as u32).wrapping_mul(16777619);
+#[unsafe(no_mangle)]
+pub extern "C" fn ts_free_byte_ranges(ptr: *mut ByteRange, len: usize) {
+    if !ptr.is_null() {
+        unsafe { drop(Box::from_raw(std::ptr::slice_from_raw_parts_mut(ptr, len))) }
     }
-    (h & 0xFFFF) as u16
+}
+
+/// Walks the whole tree (not just leaves, since an ERROR node can itself
+/// have children) and collects every node where `is_error()` or
+/// `is_missing()` is true, so the host can render squiggles and messages
+/// instead of just checking `has_error()`.
+#[unsafe(no_mangle)]
+pub extern "C" fn ts_get_diagnostics(
+    source: *const c_char,
+    lang_id: c_int,
+    out_diagnostics: *mut *mut Diagnostic,
+) -> usize {
+    if source.is_null() || out_diagnostics.is_null() {
+        return 0;
+    }
+    let c_src = unsafe { CStr::from_ptr(source) };
+    let Ok(code) = c_src.to_str() else { return 0 };
+
+    let mut parser = Parser::new();
+    let Some(lang) = get_language(lang_id) else {
+        return 0;
+    };
+    if parser.set_language(&lang).is_err() {
+        return 0;
+    }
+    let Some(tree) = parser.parse(code, None) else {
+        return 0;
+    };
+
+    let mut diagnostics: Vec<Diagnostic> = Vec::new();
+    let mut stack = Vec::with_capacity(256);
+    stack.push(tree.root_node());
+
+    while let Some(node) = stack.pop() {
+        if node.is_error() || node.is_missing() {
+            let start_pos = node.start_position();
+            let end_pos = node.end_position();
+            let node_kind = CString::new(node.kind()).unwrap_or_default();
+            diagnostics.push(Diagnostic {
+                start: node.start_byte() as u32,
+                end: node.end_byte() as u32,
+                start_row: start_pos.row as u32,
+                start_col: start_pos.column as u32,
+                end_row: end_pos.row as u32,
+                end_col: end_pos.column as u32,
+                kind: if node.is_missing() {
+                    DiagnosticKind::Missing
+                } else {
+                    DiagnosticKind::Error
+                },
+                node_kind: node_kind.into_raw(),
+            });
+        }
+        for i in 0..node.child_count() {
+            if let Some(child) = node.child(i) {
+                stack.push(child);
+            }
+        }
+    }
+
+    let len = diagnostics.len();
+    let boxed = diagnostics.into_boxed_slice();
+    let ptr = boxed.as_ptr() as *mut Diagnostic;
+    std::mem::forget(boxed);
+    unsafe {
+        *out_diagnostics = ptr;
+    }
+    len
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn ts_free_diagnostics(ptr: *mut Diagnostic, len: usize) {
+    if ptr.is_null() {
+        return;
+    }
+    unsafe {
+        let slice = &mut *std::ptr::slice_from_raw_parts_mut(ptr, len);
+        for diag in slice.iter_mut() {
+            if !diag.node_kind.is_null() {
+                drop(CString::from_raw(diag.node_kind));
+            }
+        }
+        drop(Box::from_raw(slice));
+    }
+}
+
+/// The stable, versioned table of syntax kinds this gateway can report.
+/// `kind_id` is the index of a name into this table, not a hash, so it
+/// never collides and can always be turned back into a name via
+/// `ts_kind_name`. Entries come from the capture names used across the
+/// highlight queries above plus the common leaf node kinds they match
+/// against; anything not listed here folds into `"unknown"` rather than
+/// silently colliding with an unrelated kind.
+///
+/// Bumping this table is a breaking change for any host that persists
+/// `kind_id` values (e.g. in a cached theme mapping) across versions.
+static SYNTAX_KINDS: &[&str] = &[
+    "unknown",
+    "function",
+    "function.call",
+    "type",
+    "trait",
+    "constant",
+    "keyword",
+    "keyword.type",
+    "string",
+    "number",
+    "comment",
+    "variable",
+    "identifier",
+    "type_identifier",
+    "string_literal",
+    "char_literal",
+    "rune_literal",
+    "number_literal",
+    "integer_literal",
+    "float_literal",
+    "primitive_type",
+    "whitespace",
+    "heading",
+    "emphasis",
+    "strong",
+    "inline_code",
+    "link_text",
+    "link_destination",
+    "image_alt",
+    "list_item",
+    "blockquote",
+    "separator",
+    "atx_heading",
+    "strong_emphasis",
+    "code_span",
+    "image_description",
+    "list_marker_minus",
+    "list_marker_plus",
+    "list_marker_star",
+    "block_quote_marker",
+    "thematic_break",
+    "fenced_code_block",
+    "info_string",
+    "language",
+    "code_fence_content",
+    // Keyword leaves (`ts_get_tokens` reports them by their literal text,
+    // which is the grammar's node `kind()` for a keyword token; these are
+    // the union of the keyword lists in the highlight queries above).
+    "fn",
+    "let",
+    "mut",
+    "struct",
+    "enum",
+    "impl",
+    "use",
+    "as",
+    "pub",
+    "crate",
+    "return",
+    "if",
+    "else",
+    "match",
+    "while",
+    "for",
+    "loop",
+    "in",
+    "true",
+    "false",
+    "const",
+    "static",
+    "proc",
+    "import",
+    "package",
+    "foreign",
+    "when",
+    "break",
+    "continue",
+    "case",
+    "switch",
+    "defer",
+    "using",
+    "def",
+    "class",
+    "elif",
+    "from",
+    "yield",
+    "try",
+    "except",
+    "finally",
+    "assert",
+    "with",
+    "lambda",
+    "global",
+    "nonlocal",
+    "del",
+    "pass",
+    "raise",
+    "True",
+    "False",
+    "None",
+    "typedef",
+    "union",
+    "void",
+    "extern",
+    "sizeof",
+    // Common punctuation/operator leaves.
+    "(",
+    ")",
+    "{",
+    "}",
+    "[",
+    "]",
+    ";",
+    ":",
+    ",",
+    ".",
+    "->",
+    "=>",
+    "=",
+    "==",
+    "!=",
+    "<",
+    ">",
+    "<=",
+    ">=",
+    "+",
+    "-",
+    "*",
+    "/",
+    "%",
+    "&",
+    "|",
+    "^",
+    "!",
+    "&&",
+    "||",
+    "::",
+];
+
+const UNKNOWN_KIND_ID: u16 = 0;
+
+/// Looks up `kind`'s stable index in `SYNTAX_KINDS`, falling back to the
+/// `"unknown"` bucket for anything not in the table.
+fn syntax_kind_id(kind: &str) -> u16 {
+    SYNTAX_KINDS
+        .iter()
+        .position(|&k| k == kind)
+        .map(|i| i as u16)
+        .unwrap_or(UNKNOWN_KIND_ID)
+}
+
+/// Returns the name for `kind_id`, or null if it's out of range. The
+/// returned pointer is valid for the lifetime of the process; the host
+/// does not need to (and must not) free it.
+#[unsafe(no_mangle)]
+pub extern "C" fn ts_kind_name(kind_id: u16) -> *const c_char {
+    static NAMES: std::sync::OnceLock<Vec<CString>> = std::sync::OnceLock::new();
+    let names = NAMES.get_or_init(|| {
+        SYNTAX_KINDS
+            .iter()
+            .map(|s| CString::new(*s).unwrap())
+            .collect()
+    });
+    names
+        .get(kind_id as usize)
+        .map(|c| c.as_ptr())
+        .unwrap_or(std::ptr::null())
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn ts_kind_count() -> usize {
+    SYNTAX_KINDS.len()
 }
 
 fn get_language(lang_id: c_int) -> Option<Language> {
@@ -333,10 +753,207 @@ fn get_language(lang_id: c_int) -> Option<Language> {
         1 => tree_sitter_c::LANGUAGE.into(),
         2 => tree_sitter_python::LANGUAGE.into(),
         3 => tree_sitter_odin::LANGUAGE.into(),
+        4 => tree_sitter_md::LANGUAGE.into(),
         _ => return None,
     })
 }
 
+fn highlight_query_for(lang_id: c_int) -> Option<&'static str> {
+    match lang_id {
+        0 => Some(RUST_HIGHLIGHT_QUERY),
+        1 => Some(C_HIGHLIGHT_QUERY),
+        2 => Some(PYTHON_HIGHLIGHT_QUERY),
+        3 => Some(ODIN_HIGHLIGHT_QUERY),
+        4 => Some(MARKDOWN_HIGHLIGHT_QUERY),
+        _ => None,
+    }
+}
+
+fn injection_query_for(lang_id: c_int) -> Option<&'static str> {
+    match lang_id {
+        4 => Some(MARKDOWN_INJECTION_QUERY),
+        _ => None,
+    }
+}
+
+/// Maps an injection `@injection.language` capture's text (a fenced code
+/// block's info string, e.g. "rust" or "py") to this gateway's `lang_id`.
+/// Returns `None` for anything we don't have a grammar for, so the
+/// caller can leave that region unhighlighted instead of failing.
+fn lang_id_for_injection_name(name: &str) -> Option<c_int> {
+    match name {
+        "rust" | "rs" => Some(0),
+        "c" => Some(1),
+        "python" | "py" => Some(2),
+        "odin" => Some(3),
+        _ => None,
+    }
+}
+
+/// How deep an injection may nest (code block inside a quote inside a
+/// code block, ...) before we stop recursing, to guard against a
+/// malicious or cyclic injection query recursing forever.
+const MAX_INJECTION_DEPTH: u32 = 4;
+
+/// Translates a position from a nested parse's local coordinate space
+/// (where row 0 starts at the injection site) back into the host
+/// document's coordinate space. Only the first line needs its column
+/// shifted, since every other line's column is already absolute.
+fn translate_point(row: u32, col: u32, base_row: u32, base_col: u32) -> (u32, u32) {
+    if row == 0 {
+        (base_row, base_col + col)
+    } else {
+        (base_row + row, col)
+    }
+}
+
+/// Parses `code` with `lang_id`'s grammar, runs its highlight query, and
+/// recursively splices in any injected nested-language regions (e.g. a
+/// fenced \`\`\`rust block inside Markdown), returning one merged,
+/// offset-correct token stream. `base_offset`/`base_row`/`base_col` are
+/// where `code` begins in the host document; top-level callers pass
+/// `(0, 0, 0)`.
+fn collect_highlight_tokens(
+    code: &str,
+    lang_id: c_int,
+    base_offset: u32,
+    base_row: u32,
+    base_col: u32,
+    depth: u32,
+) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    if depth > MAX_INJECTION_DEPTH {
+        return tokens;
+    }
+    let Some(lang) = get_language(lang_id) else {
+        return tokens;
+    };
+    let mut parser = Parser::new();
+    if parser.set_language(&lang).is_err() {
+        return tokens;
+    }
+    let Some(tree) = parser.parse(code, None) else {
+        return tokens;
+    };
+    let root = tree.root_node();
+    let bytes = code.as_bytes();
+
+    if let Some(query_source) = highlight_query_for(lang_id) {
+        match tree_sitter::Query::new(&lang, query_source) {
+            Ok(query) => {
+                let mut cursor = tree_sitter::QueryCursor::new();
+                let mut matches = cursor.matches(&query, root, bytes);
+                loop {
+                    matches.advance();
+                    let Some(m) = matches.get() else { break };
+                    for cap in m.captures {
+                        let node = cap.node;
+                        let name = query.capture_names()[cap.index as usize];
+                        let start_pos = node.start_position();
+                        let end_pos = node.end_position();
+                        let (start_row, start_col) = translate_point(
+                            start_pos.row as u32,
+                            start_pos.column as u32,
+                            base_row,
+                            base_col,
+                        );
+                        let (end_row, end_col) = translate_point(
+                            end_pos.row as u32,
+                            end_pos.column as u32,
+                            base_row,
+                            base_col,
+                        );
+                        tokens.push(Token {
+                            start: base_offset + node.start_byte() as u32,
+                            end: base_offset + node.end_byte() as u32,
+                            kind_id: syntax_kind_id(name),
+                            _pad: 0,
+                            start_row,
+                            start_col,
+                            end_row,
+                            end_col,
+                        });
+                    }
+                }
+            }
+            Err(err) => {
+                eprintln!("Failed to compile highlight query for lang {}: {:?}", lang_id, err);
+            }
+        }
+    }
+
+    if depth == MAX_INJECTION_DEPTH {
+        return tokens;
+    }
+    let Some(injection_source) = injection_query_for(lang_id) else {
+        return tokens;
+    };
+    let injection_query = match tree_sitter::Query::new(&lang, injection_source) {
+        Ok(q) => q,
+        Err(err) => {
+            eprintln!("Failed to compile injection query for lang {}: {:?}", lang_id, err);
+            return tokens;
+        }
+    };
+
+    let mut cursor = tree_sitter::QueryCursor::new();
+    let mut matches = cursor.matches(&injection_query, root, bytes);
+    loop {
+        matches.advance();
+        let Some(m) = matches.get() else { break };
+
+        let mut content_range: Option<(usize, usize, Point, Point)> = None;
+        let mut lang_name: Option<&str> = None;
+        for cap in m.captures {
+            match injection_query.capture_names()[cap.index as usize] {
+                "injection.content" => {
+                    content_range = Some((
+                        cap.node.start_byte(),
+                        cap.node.end_byte(),
+                        cap.node.start_position(),
+                        cap.node.end_position(),
+                    ));
+                }
+                "injection.language" => {
+                    lang_name = cap.node.utf8_text(bytes).ok();
+                }
+                _ => {}
+            }
+        }
+
+        let (Some((start, end, content_start_pos, _content_end_pos)), Some(name)) =
+            (content_range, lang_name)
+        else {
+            continue;
+        };
+        // Unknown injection language: leave the region unhighlighted
+        // rather than failing the whole call.
+        let Some(nested_lang_id) = lang_id_for_injection_name(name) else {
+            continue;
+        };
+        if start > end || end > code.len() {
+            continue;
+        }
+
+        let (nested_base_row, nested_base_col) = translate_point(
+            content_start_pos.row as u32,
+            content_start_pos.column as u32,
+            base_row,
+            base_col,
+        );
+        tokens.extend(collect_highlight_tokens(
+            &code[start..end],
+            nested_lang_id,
+            base_offset + start as u32,
+            nested_base_row,
+            nested_base_col,
+            depth + 1,
+        ));
+    }
+
+    tokens
+}
+
 #[unsafe(no_mangle)]
 pub extern "C" fn ts_parse(source: *const c_char, lang_id: c_int) -> TSResult {
     let c_str = unsafe { CStr::from_ptr(source) };
@@ -395,6 +1012,175 @@ pub extern "C" fn ts_parse(source: *const c_char, lang_id: c_int) -> TSResult {
     }
 }
 
+/// Applies an edit to `old_tree_ptr` and reparses only the affected
+/// region, reusing unchanged subtrees. The new tree and its s-expression
+/// are written to `out_result`; the byte ranges that actually changed
+/// (per `Tree::changed_ranges`) are written to `out_changed_ranges`, and
+/// the number of such ranges is the return value. Free the ranges with
+/// `ts_free_byte_ranges` and the result with `ts_free_result`.
+///
+/// If `old_tree_ptr` is null, or the edit points are inconsistent (byte
+/// offsets don't agree with row/column), this falls back to a full
+/// reparse of `new_source` and reports the whole document as changed,
+/// rather than risk corrupting the tree.
+#[unsafe(no_mangle)]
+pub extern "C" fn ts_reparse(
+    old_tree_ptr: *mut Tree,
+    new_source: *const c_char,
+    lang_id: c_int,
+    start_byte: u32,
+    old_end_byte: u32,
+    new_end_byte: u32,
+    start_row: u32,
+    start_col: u32,
+    old_end_row: u32,
+    old_end_col: u32,
+    new_end_row: u32,
+    new_end_col: u32,
+    out_result: *mut TSResult,
+    out_changed_ranges: *mut *mut ByteRange,
+) -> usize {
+    let fail = |msg: &str| -> TSResult {
+        TSResult {
+            tree_ptr: std::ptr::null_mut(),
+            root_sexpr: CString::new(msg).unwrap().into_raw(),
+        }
+    };
+
+    if out_result.is_null() || out_changed_ranges.is_null() || new_source.is_null() {
+        return 0;
+    }
+    unsafe {
+        *out_changed_ranges = std::ptr::null_mut();
+    }
+
+    let c_str = unsafe { CStr::from_ptr(new_source) };
+    let code = c_str.to_str().unwrap_or("");
+
+    let mut parser = Parser::new();
+    let language = match get_language(lang_id) {
+        Some(lang) => lang,
+        None => {
+            eprintln!("Unsupported language id {}", lang_id);
+            unsafe { *out_result = fail("(unsupported)") };
+            return 0;
+        }
+    };
+    if let Err(err) = parser.set_language(&language) {
+        eprintln!("Failed to set language: {:?}", err);
+        unsafe { *out_result = fail("(language error)") };
+        return 0;
+    }
+
+    let old_tree: Option<&mut Tree> = if old_tree_ptr.is_null() {
+        None
+    } else {
+        unsafe { old_tree_ptr.as_mut() }
+    };
+
+    let consistent = edit_is_consistent(
+        start_byte,
+        old_end_byte,
+        new_end_byte,
+        start_row,
+        start_col,
+        old_end_row,
+        old_end_col,
+        new_end_row,
+        new_end_col,
+    );
+
+    let (new_tree, changed): (Tree, Vec<ByteRange>) = match old_tree {
+        Some(old_tree) if consistent => {
+            let edit = InputEdit {
+                start_byte: start_byte as usize,
+                old_end_byte: old_end_byte as usize,
+                new_end_byte: new_end_byte as usize,
+                start_position: Point::new(start_row as usize, start_col as usize),
+                old_end_position: Point::new(old_end_row as usize, old_end_col as usize),
+                new_end_position: Point::new(new_end_row as usize, new_end_col as usize),
+            };
+            old_tree.edit(&edit);
+
+            let new_tree = match parser.parse(code, Some(old_tree)) {
+                Some(t) => t,
+                None => {
+                    unsafe { *out_result = fail("(parse failed)") };
+                    return 0;
+                }
+            };
+            // `Tree::changed_ranges` is called on the old tree (the one
+            // just passed to `parse`), with the new tree as the argument
+            // - not the other way around - per its own documentation.
+            let changed = old_tree
+                .changed_ranges(&new_tree)
+                .map(|r| ByteRange {
+                    start: r.start_byte as u32,
+                    end: r.end_byte as u32,
+                })
+                .collect();
+            (new_tree, changed)
+        }
+        _ => match parser.parse(code, None) {
+            Some(t) => {
+                let whole_doc = ByteRange {
+                    start: 0,
+                    end: code.len() as u32,
+                };
+                (t, vec![whole_doc])
+            }
+            None => {
+                unsafe { *out_result = fail("(parse failed)") };
+                return 0;
+            }
+        },
+    };
+
+    let len = changed.len();
+    let boxed = changed.into_boxed_slice();
+    let ptr = boxed.as_ptr() as *mut ByteRange;
+    std::mem::forget(boxed);
+
+    let sexpr_str = new_tree.root_node().to_sexp();
+    let sexpr = CString::new(sexpr_str).unwrap();
+    unsafe {
+        *out_result = TSResult {
+            tree_ptr: Box::into_raw(Box::new(new_tree)),
+            root_sexpr: sexpr.into_raw(),
+        };
+        *out_changed_ranges = ptr;
+    }
+    len
+}
+
+/// Returns true if the edit's byte offsets and row/column endpoints are
+/// mutually consistent (monotonic and in agreement with each other), so
+/// the caller can safely apply it via `Tree::edit` without corrupting the
+/// tree. This does not validate against the actual source text.
+///
+/// Takes the raw `ts_reparse` edit fields one-to-one rather than an
+/// `InputEdit` so it can validate them *before* constructing one.
+#[allow(clippy::too_many_arguments)]
+fn edit_is_consistent(
+    start_byte: u32,
+    old_end_byte: u32,
+    new_end_byte: u32,
+    start_row: u32,
+    start_col: u32,
+    old_end_row: u32,
+    old_end_col: u32,
+    new_end_row: u32,
+    new_end_col: u32,
+) -> bool {
+    if start_byte > old_end_byte || start_byte > new_end_byte {
+        return false;
+    }
+    let start_point_ok = |end_row: u32, end_col: u32| {
+        end_row > start_row || (end_row == start_row && end_col >= start_col)
+    };
+    start_point_ok(old_end_row, old_end_col) && start_point_ok(new_end_row, new_end_col)
+}
+
 #[unsafe(no_mangle)]
 pub extern "C" fn ts_free_result(result: TSResult) {
     unsafe {
@@ -423,6 +1209,19 @@ mod tests {
         let root = tree.root_node();
         assert_eq!(root.kind(), "source_file");
         assert!(!root.has_error(), "Rust parse tree contains errors");
+
+        let c_code = CString::new(code).unwrap();
+        let mut out_tokens: *mut Token = std::ptr::null_mut();
+        let count = ts_get_tokens(c_code.as_ptr(), 0, &mut out_tokens);
+        assert!(count > 0);
+        let tokens = unsafe { std::slice::from_raw_parts(out_tokens, count) };
+        let fn_token = tokens
+            .iter()
+            .find(|t| t.start == 0)
+            .expect("expected the leading `fn` token");
+        assert_eq!((fn_token.start_row, fn_token.start_col), (0, 0));
+        assert_eq!((fn_token.end_row, fn_token.end_col), (0, 2));
+        ts_free_tokens(out_tokens, count);
     }
 
     #[test]
@@ -455,4 +1254,233 @@ mod tests {
     fn invalid_language_id_returns_none() {
         assert!(get_language(999).is_none());
     }
+
+    #[test]
+    fn diagnostics_distinguish_error_and_missing_nodes() {
+        // Dangling parameter list (tree-sitter inserts a MISSING `)` to
+        // recover) whose body also has a bare `=` the grammar can't
+        // make sense of (wrapped in an ERROR node).
+        let src = "fn foo(a: i32 { let x = ; }";
+        let c_src = CString::new(src).unwrap();
+        let mut out_diagnostics: *mut Diagnostic = std::ptr::null_mut();
+        let count = ts_get_diagnostics(c_src.as_ptr(), 0, &mut out_diagnostics);
+        assert!(count > 0);
+
+        let diagnostics = unsafe { std::slice::from_raw_parts(out_diagnostics, count) };
+        let missing = diagnostics
+            .iter()
+            .find(|d| d.kind == DiagnosticKind::Missing)
+            .expect("expected a MISSING diagnostic for the unclosed parameter list");
+        assert_eq!(missing.start, missing.end, "a MISSING node consumes no bytes");
+
+        let error = diagnostics
+            .iter()
+            .find(|d| d.kind == DiagnosticKind::Error)
+            .expect("expected an ERROR diagnostic for the bare `=`");
+        assert!(error.start < error.end);
+        assert!(error.end <= src.len() as u32);
+
+        ts_free_diagnostics(out_diagnostics, count);
+    }
+
+    #[test]
+    fn kind_name_round_trips_through_kind_id() {
+        let id = syntax_kind_id("primitive_type");
+        assert_ne!(id, UNKNOWN_KIND_ID);
+        let name = unsafe { CStr::from_ptr(ts_kind_name(id)) };
+        assert_eq!(name.to_str().unwrap(), "primitive_type");
+
+        assert!(ts_kind_name(ts_kind_count() as u16).is_null());
+    }
+
+    #[test]
+    fn reparse_incremental_matches_full_reparse() {
+        let old_src = "fn double(x:i32)->i32{x*2}";
+        let old_code = CString::new(old_src).unwrap();
+        let old_result = ts_parse(old_code.as_ptr(), 0);
+        assert!(!old_result.tree_ptr.is_null());
+
+        // Insert `+1` before the closing brace, turning `x*2` into
+        // `x*2+1`. Unlike a same-length rename, this actually changes
+        // the tree's shape (a new binary_expression wraps the old one),
+        // so `changed_ranges` is guaranteed non-empty.
+        let insert_at = old_src.rfind('}').unwrap() as u32;
+        let new_src = format!(
+            "{}+1{}",
+            &old_src[..insert_at as usize],
+            &old_src[insert_at as usize..]
+        );
+        let new_code = CString::new(new_src.as_str()).unwrap();
+        let mut out_result = TSResult {
+            tree_ptr: std::ptr::null_mut(),
+            root_sexpr: std::ptr::null_mut(),
+        };
+        let mut out_changed_ranges: *mut ByteRange = std::ptr::null_mut();
+
+        let count = ts_reparse(
+            old_result.tree_ptr,
+            new_code.as_ptr(),
+            0,
+            insert_at, insert_at, insert_at + 2,
+            0, insert_at,
+            0, insert_at,
+            0, insert_at + 2,
+            &mut out_result,
+            &mut out_changed_ranges,
+        );
+
+        assert!(!out_result.tree_ptr.is_null());
+        assert!(count >= 1, "expected at least one changed range");
+        let incremental_sexp = unsafe { (*out_result.tree_ptr).root_node().to_sexp() };
+
+        let mut parser = Parser::new();
+        let lang = get_language(0).expect("Rust language not loaded");
+        parser.set_language(&lang).unwrap();
+        let full_tree = parser.parse(new_src, None).unwrap();
+        assert_eq!(incremental_sexp, full_tree.root_node().to_sexp());
+
+        ts_free_byte_ranges(out_changed_ranges, count);
+        ts_free_result(out_result);
+        ts_free_result(old_result);
+    }
+
+    #[test]
+    fn reparse_falls_back_to_full_reparse_when_old_tree_is_null() {
+        let new_src = "fn triple(x:i32)->i32{x*2}";
+        let new_code = CString::new(new_src).unwrap();
+        let mut out_result = TSResult {
+            tree_ptr: std::ptr::null_mut(),
+            root_sexpr: std::ptr::null_mut(),
+        };
+        let mut out_changed_ranges: *mut ByteRange = std::ptr::null_mut();
+
+        let count = ts_reparse(
+            std::ptr::null_mut(),
+            new_code.as_ptr(),
+            0,
+            0, 0, 0,
+            0, 0,
+            0, 0,
+            0, 0,
+            &mut out_result,
+            &mut out_changed_ranges,
+        );
+
+        assert!(!out_result.tree_ptr.is_null());
+        assert_eq!(count, 1, "full reparse should report the whole document as changed");
+        unsafe {
+            assert!(!out_changed_ranges.is_null());
+            let ranges = std::slice::from_raw_parts(out_changed_ranges, count);
+            assert_eq!(ranges[0].start, 0);
+            assert_eq!(ranges[0].end, new_src.len() as u32);
+        }
+
+        ts_free_byte_ranges(out_changed_ranges, count);
+        ts_free_result(out_result);
+    }
+
+    #[test]
+    fn reparse_rejects_null_source() {
+        let mut out_result = TSResult {
+            tree_ptr: std::ptr::null_mut(),
+            root_sexpr: std::ptr::null_mut(),
+        };
+        let mut out_changed_ranges: *mut ByteRange = std::ptr::null_mut();
+
+        let count = ts_reparse(
+            std::ptr::null_mut(),
+            std::ptr::null(),
+            0,
+            0, 0, 0,
+            0, 0,
+            0, 0,
+            0, 0,
+            &mut out_result,
+            &mut out_changed_ranges,
+        );
+
+        assert_eq!(count, 0);
+        assert!(out_result.tree_ptr.is_null());
+        assert!(out_changed_ranges.is_null());
+    }
+
+    #[test]
+    fn tokens_with_trivia_cover_source_exactly() {
+        // Leading/trailing whitespace exercises the head/tail gaps; the
+        // adjacent leaves inside `x*2` (no whitespace between them)
+        // exercise the zero-width-gap case.
+        let src = "  fn double(x:i32)->i32{x*2}  \n";
+        let c_src = CString::new(src).unwrap();
+        let mut out_tokens: *mut Token = std::ptr::null_mut();
+        let count = ts_get_tokens_with_trivia(c_src.as_ptr(), 0, &mut out_tokens);
+        assert!(count > 0);
+        let tokens = unsafe { std::slice::from_raw_parts(out_tokens, count) };
+
+        let whitespace_kind = syntax_kind_id("whitespace");
+
+        // Strictly gap-free: each token starts exactly where the
+        // previous one ended, and no zero-width trivia token is emitted.
+        let mut cursor = 0u32;
+        let mut reconstructed = String::new();
+        for tok in tokens {
+            assert_eq!(tok.start, cursor, "gap or overlap before byte {}", cursor);
+            if tok.kind_id == whitespace_kind {
+                assert!(tok.end > tok.start, "zero-width trivia token was emitted");
+            }
+            reconstructed.push_str(&src[tok.start as usize..tok.end as usize]);
+            cursor = tok.end;
+        }
+        assert_eq!(cursor, src.len() as u32, "token stream must cover 0..src_len");
+        assert_eq!(
+            reconstructed, src,
+            "concatenated tokens must reproduce the source exactly"
+        );
+
+        // The leading and trailing whitespace became synthetic trivia
+        // tokens covering the head and tail of the file.
+        assert_eq!(tokens.first().unwrap().kind_id, whitespace_kind);
+        assert_eq!(tokens.last().unwrap().kind_id, whitespace_kind);
+
+        ts_free_tokens(out_tokens, count);
+    }
+
+    #[test]
+    fn edit_is_consistent_rejects_byte_point_mismatch() {
+        // start_byte ahead of old_end_byte can never be valid.
+        assert!(!edit_is_consistent(10, 5, 5, 0, 10, 0, 5, 0, 5));
+        // old_end_row/col placed before start_row/col on the same line.
+        assert!(!edit_is_consistent(5, 8, 8, 0, 10, 0, 2, 0, 2));
+        // A genuinely consistent edit is accepted.
+        assert!(edit_is_consistent(3, 9, 9, 0, 3, 0, 9, 0, 9));
+    }
+
+    #[test]
+    fn markdown_injection_highlights_fenced_rust_block() {
+        let src = "# Title\n\n```rust\nfn main() {}\n```\n";
+        let c_src = CString::new(src).unwrap();
+        let mut out_tokens: *mut Token = std::ptr::null_mut();
+        // lang_id 4 is Markdown.
+        let count = ts_get_highlight_tokens(c_src.as_ptr(), 4, &mut out_tokens);
+        assert!(count > 0);
+        let tokens = unsafe { std::slice::from_raw_parts(out_tokens, count) };
+
+        // The heading comes from the Markdown block-grammar query.
+        let heading_kind = syntax_kind_id("heading");
+        assert!(tokens.iter().any(|t| t.kind_id == heading_kind));
+
+        // The injected Rust grammar should highlight `fn` as a keyword,
+        // at its absolute offset within the Markdown document (the
+        // fenced code content starts after "# Title\n\n```rust\n").
+        let fn_offset = src.find("fn main").unwrap() as u32;
+        let keyword_kind = syntax_kind_id("keyword");
+        assert!(
+            tokens
+                .iter()
+                .any(|t| t.kind_id == keyword_kind && t.start == fn_offset && t.end == fn_offset + 2),
+            "expected a keyword token for the injected `fn` at offset {}",
+            fn_offset
+        );
+
+        ts_free_tokens(out_tokens, count);
+    }
 }